@@ -1,7 +1,19 @@
+use std::any::{Any, TypeId};
+use std::borrow::Borrow;
 use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
 
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHasher};
+use memmap::Mmap;
 use parking_lot::RwLock;
+#[cfg(feature = "hot-reload")]
+use log::warn;
 
 use StoreId;
 
@@ -47,6 +59,10 @@ pub trait Asset
     /// Returns `Some` cached value if possible, otherwise `None`.
     ///
     /// For a basic implementation of a cache, please take a look at the `Cache` type.
+    /// Callers that need finer control over what happens on a miss (e.g.
+    /// never blocking on I/O in hot-path frame code) should consult a
+    /// `CacheMissPolicy` before falling back to a blocking load; see
+    /// `AssetCache::load_with_policy`.
     fn retrieve(_context: &Self::Context, _spec: &AssetSpec) -> Option<Self> {
         None
     }
@@ -88,13 +104,40 @@ impl AssetSpec {
     }
 }
 
+/// Returns the number of shards a fresh `Cache` should use: the next power
+/// of two at or above the number of available CPUs, so that concurrent
+/// inserts from different worker threads usually land on different shards
+/// and only rarely contend with each other.
+fn shard_count() -> usize {
+    num_cpus::get().next_power_of_two()
+}
+
+/// Picks the shard `spec` belongs to out of `shard_count` shards, by hashing
+/// it once with `FnvHasher` and masking off the low bits. `shard_count` must
+/// be a power of two.
+fn shard_index(spec: &AssetSpec, shard_count: usize) -> usize {
+    let mut hasher = FnvHasher::default();
+    spec.hash(&mut hasher);
+
+    hasher.finish() as usize & (shard_count - 1)
+}
+
 /// A basic implementation for a cache. This might be useful as the `Context` of
 /// an `Asset`, so that the same asset doesn't get imported twice.
 ///
-/// Because contexts have to be immutable, a `RwLock` is used. Therefore, all
-/// operations are blocking (but shouldn't block for a long time).
+/// Because contexts have to be immutable, a `RwLock` is used. Internally,
+/// the map is split into several shards (see `shard_count`), each guarded by
+/// its own `RwLock`, so inserts for `AssetSpec`s that fall into different
+/// shards don't serialize on each other. All operations are blocking, but
+/// shouldn't block for a long time.
 pub struct Cache<T> {
-    map: RwLock<FnvHashMap<AssetSpec, T>>,
+    shards: Vec<RwLock<FnvHashMap<AssetSpec, T>>>,
+    /// The change marker each entry had the last time it was loaded or
+    /// hot-reloaded, consulted by `hot_reload` to skip unchanged assets.
+    /// Sharded the same way as `shards`, so a spec's bookkeeping always
+    /// lives at the same index as its value.
+    #[cfg(feature = "hot-reload")]
+    modified: Vec<RwLock<FnvHashMap<AssetSpec, u64>>>,
 }
 
 impl<T> Cache<T>
@@ -105,39 +148,145 @@ impl<T> Cache<T>
         Default::default()
     }
 
-    /// Inserts an asset, locking the internal `RwLock` to get write access to the hash map.
+    fn shard(&self, spec: &AssetSpec) -> &RwLock<FnvHashMap<AssetSpec, T>> {
+        &self.shards[shard_index(spec, self.shards.len())]
+    }
+
+    /// Inserts an asset, locking the `RwLock` of the shard `spec` falls into
+    /// to get write access to its hash map.
     ///
     /// Returns the previous value in case there was any.
     pub fn insert(&self, spec: AssetSpec, asset: T) -> Option<T> {
-        self.map.write().insert(spec, asset)
+        self.shard(&spec).write().insert(spec, asset)
     }
 
-    /// Retrieves an asset, locking the internal `RwLock` to get read access to the hash map.
-    /// In case this asset has been inserted previously, it will be cloned and returned.
-    /// Otherwise, you'll receive `None`.
+    /// Retrieves an asset, locking the `RwLock` of the shard `spec` falls
+    /// into to get read access to its hash map. In case this asset has been
+    /// inserted previously, it will be cloned and returned. Otherwise,
+    /// you'll receive `None`.
     pub fn get(&self, spec: &AssetSpec) -> Option<T> {
-        self.map.read().get(spec).map(Clone::clone)
+        self.shard(spec).read().get(spec).map(Clone::clone)
     }
 
     /// Deletes all cached values, except the ones `f` returned `true` for.
     /// May be used when you're about to clear unused assets (see `Asset::clear`).
     ///
-    /// Blocks the calling thread for getting write access to the hash map.
-    pub fn retain<F>(&self, f: F)
+    /// Blocks the calling thread for getting write access to each shard's
+    /// hash map, one shard at a time.
+    pub fn retain<F>(&self, mut f: F)
         where F: FnMut(&AssetSpec, &mut T) -> bool
     {
-        self.map.write().retain(f);
+        for shard in &self.shards {
+            shard.write().retain(&mut f);
+        }
     }
 
-    /// Deletes all cached values after locking the `RwLock`.
+    /// Deletes all cached values, locking each shard's `RwLock` in turn.
     pub fn clear_all(&self) {
-        self.map.write().clear();
+        for shard in &self.shards {
+            shard.write().clear();
+        }
     }
 }
 
 impl<T> Default for Cache<T> {
     fn default() -> Self {
-        Cache { map: Default::default() }
+        let shard_count = shard_count();
+
+        Cache {
+            shards: (0..shard_count).map(|_| Default::default()).collect(),
+            #[cfg(feature = "hot-reload")]
+            modified: (0..shard_count).map(|_| Default::default()).collect(),
+        }
+    }
+}
+
+/// Provides fresh bytes for an asset's source together with a marker (a
+/// modification time, a content hash, anything that only changes when the
+/// bytes do) that [`Cache::hot_reload`] can compare against the last one it
+/// saw to decide whether the entry needs to be re-parsed.
+#[cfg(feature = "hot-reload")]
+pub trait Watch {
+    /// Returns the current bytes for `spec` and a marker for their current
+    /// state, or `None` if the asset can no longer be found.
+    fn watch(&self, spec: &AssetSpec) -> Option<(Vec<u8>, u64)>;
+}
+
+#[cfg(feature = "hot-reload")]
+impl<T> Cache<T>
+    where T: Asset + Clone
+{
+    /// Like `insert`, but also records `marker` as the entry's baseline
+    /// change marker.
+    ///
+    /// Plain `insert` has no marker to record, so an entry inserted that
+    /// way has no baseline: the first `hot_reload` pass that reaches it
+    /// will unconditionally re-parse it, even if its bytes haven't
+    /// actually changed since it was loaded. Callers that already have the
+    /// marker on hand when they load an asset (e.g. right after fetching
+    /// the bytes `asset` was built from) should use `insert_watched`
+    /// instead, so `hot_reload` can skip it until it genuinely changes.
+    pub fn insert_watched(&self, spec: AssetSpec, asset: T, marker: u64) -> Option<T> {
+        self.modified[shard_index(&spec, self.modified.len())].write().insert(spec.clone(), marker);
+
+        self.insert(spec, asset)
+    }
+
+    /// Re-parses every cached asset of extension `F::extension()` whose
+    /// source has changed since it was last loaded (or last reloaded), and
+    /// overwrites the cached value in place, behind the shard's `RwLock`.
+    /// Only later `get` calls see the new value: values handed out by
+    /// earlier `get` calls are clones and keep the old one.
+    ///
+    /// A parse or conversion error is logged and the previously cached
+    /// asset is kept; hot-reloading is meant for iterating on content, not
+    /// for surfacing hard failures.
+    ///
+    /// Entries that were inserted via plain `insert` (with no baseline
+    /// marker recorded) are unconditionally re-parsed the first time this
+    /// runs; see `insert_watched` to avoid that.
+    ///
+    /// Only available with the `hot-reload` feature enabled, so the extra
+    /// bookkeeping this needs isn't even compiled into release builds.
+    pub fn hot_reload<F, W>(&self, format: &F, context: &T::Context, watch: &W)
+        where F: Format<Data = T::Data>,
+              W: Watch,
+    {
+        for (map, modified) in self.shards.iter().zip(self.modified.iter()) {
+            let mut modified = modified.write();
+
+            for (spec, asset) in map.write().iter_mut() {
+                if spec.ext != F::extension() {
+                    continue;
+                }
+
+                let (bytes, marker) = match watch.watch(spec) {
+                    Some(pair) => pair,
+                    None => continue,
+                };
+                if modified.get(spec).map_or(false, |&last| last == marker) {
+                    continue;
+                }
+
+                let data = match format.parse(bytes) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        warn!("failed to re-parse `{}` for hot-reload: {}", spec.name, err);
+                        continue;
+                    }
+                };
+
+                match T::from_data(data, context) {
+                    Ok(new_asset) => {
+                        *asset = new_asset;
+                        modified.insert(spec.clone(), marker);
+                    }
+                    Err(err) => {
+                        warn!("failed to rebuild `{}` from reloaded data: {}", spec.name, err);
+                    }
+                }
+            }
+        }
     }
 }
 
@@ -163,4 +312,910 @@ pub trait Format
 
     /// Reads the given bytes and produces asset data.
     fn parse(&self, bytes: Vec<u8>) -> Result<Self::Data, Self::Error>;
+
+    /// A richer entry point for formats whose source produces more than a
+    /// single asset value: a glTF scene, a sprite atlas, a level file that
+    /// references other assets by path, and so on.
+    ///
+    /// The default implementation just wraps `parse`'s result in a
+    /// `LoadedAsset` with no labeled sub-assets and no declared
+    /// dependencies; override it when your format needs to emit either.
+    fn parse_with_deps(&self, bytes: Vec<u8>) -> Result<LoadedAsset<Self::Data>, Self::Error> {
+        self.parse(bytes).map(LoadedAsset::new)
+    }
+}
+
+/// A type-erased sub-asset produced alongside the primary value of a
+/// `LoadedAsset`, stored without its concrete type being known to the
+/// `Format` machinery.
+pub struct ErasedAsset {
+    /// `TypeId::of::<A>()` for the `A` passed to `new`, i.e. the type a
+    /// caller would ask `AssetCache::get_labeled::<A>` for — *not*
+    /// `self.value.type_id()`, which would be `TypeId::of::<Handle<A>>()`
+    /// since `value` is the boxed handle, not the bare asset. Keeping this
+    /// separate is what lets `AssetCache::load` key a label entry the same
+    /// way `get_labeled` probes for it.
+    type_id: TypeId,
+    value: Box<dyn Any + Send + Sync>,
+}
+
+impl ErasedAsset {
+    /// Erases the type of `asset` so it can be stored in a `LoadedAsset`'s
+    /// `labeled` map alongside sub-assets of other types.
+    ///
+    /// `asset` is wrapped in a `Handle` internally, the same as a primary
+    /// asset is when `AssetCache::load` caches it; this keeps labeled
+    /// sub-assets reachable the same way primary ones are, instead of being
+    /// stored bare and only reachable through `get_labeled`.
+    pub fn new<A: Send + Sync + 'static>(asset: A) -> Self {
+        ErasedAsset {
+            type_id: TypeId::of::<A>(),
+            value: Box::new(Handle::new(asset)),
+        }
+    }
+
+    fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
+    fn into_boxed_any(self) -> Box<dyn Any + Send + Sync> {
+        self.value
+    }
+}
+
+/// The result of parsing a `Format` through its `parse_with_deps` entry
+/// point: the primary asset data, any named sub-assets it produced (e.g. a
+/// level's spawn points, addressable as `"level.ron#spawn_point"`), and the
+/// other assets it depends on.
+pub struct LoadedAsset<A> {
+    /// The primary value, passed on to `Asset::from_data` like `parse`'s
+    /// result would be.
+    pub value: A,
+    /// Named sub-assets this parse also produced, keyed by label.
+    pub labeled: FnvHashMap<Box<str>, ErasedAsset>,
+    /// Other assets this one depends on. `AssetCache` records these as
+    /// edges so that invalidating a dependency can invalidate whatever
+    /// depends on it.
+    pub dependencies: Vec<AssetSpec>,
+}
+
+impl<A> LoadedAsset<A> {
+    /// Wraps `value` with no labeled sub-assets and no dependencies.
+    pub fn new(value: A) -> Self {
+        LoadedAsset {
+            value,
+            labeled: Default::default(),
+            dependencies: Vec::new(),
+        }
+    }
+
+    /// Attaches a named sub-asset to this `LoadedAsset`.
+    pub fn with_label<L: Into<Box<str>>>(mut self, label: L, asset: ErasedAsset) -> Self {
+        self.labeled.insert(label.into(), asset);
+        self
+    }
+
+    /// Declares that this asset depends on `dependency`.
+    pub fn with_dependency(mut self, dependency: AssetSpec) -> Self {
+        self.dependencies.push(dependency);
+        self
+    }
+}
+
+/// A reference-counted handle to an asset stored in an `AssetCache`.
+///
+/// Cloning a `Handle` is cheap and all clones refer to the same underlying
+/// value.
+pub struct Handle<A>(Arc<A>);
+
+impl<A> Handle<A> {
+    fn new(asset: A) -> Self {
+        Handle(Arc::new(asset))
+    }
+}
+
+impl<A> Clone for Handle<A> {
+    fn clone(&self) -> Self {
+        Handle(self.0.clone())
+    }
+}
+
+impl<A: fmt::Debug> fmt::Debug for Handle<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Handle").field(&*self.0).finish()
+    }
+}
+
+impl<A> Deref for Handle<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.0
+    }
+}
+
+/// The owned form of an `AssetCache` entry key: a concrete asset type plus
+/// its name. This is what `AssetCache` actually stores its entries under.
+#[repr(C)]
+#[derive(Clone, Eq, PartialEq)]
+struct OwnedKey {
+    type_id: TypeId,
+    name: Box<str>,
+}
+
+impl Hash for OwnedKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.type_id.hash(state);
+        self.name.hash(state);
+    }
+}
+
+/// A borrowed view of an `OwnedKey`, used to probe `AssetCache`'s map
+/// without allocating a `Box<str>` for every lookup.
+#[repr(C)]
+#[derive(Eq, PartialEq)]
+struct AccessKey<'a> {
+    type_id: TypeId,
+    name: &'a str,
+}
+
+impl<'a> Hash for AccessKey<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.type_id.hash(state);
+        self.name.hash(state);
+    }
+}
+
+impl<'a> Borrow<AccessKey<'a>> for OwnedKey {
+    // SAFETY / INVARIANT: `OwnedKey` and `AccessKey` are both `#[repr(C)]`
+    // with the same fields in the same order, and `Box<str>` shares `&str`'s
+    // fat-pointer representation, so reinterpreting one as the other reads
+    // back the same bytes. That only makes the *read* sound, though: the
+    // trait signature hands back `&AccessKey<'a>` for a caller-chosen `'a`
+    // that this function body never actually constrains to `&self`'s
+    // lifetime, so nothing stops a future caller from copying the `name:
+    // &'a str` field back out of the reference and holding onto it after
+    // `self` (and the `Box<str>` it points into) is gone.
+    //
+    // This impl only exists so `FnvHashMap<OwnedKey, _>::get` can accept a
+    // borrowed `&AccessKey<'_>` without allocating a `Box<str>` per lookup;
+    // every call site in this file (`AssetCache::load`, `get_labeled`, ...)
+    // only ever passes the result straight into `.get(..)` and never stores
+    // it, extracts fields out of it, or returns it to a caller. Keep it that
+    // way, or find a way to express the borrow without reaching for a
+    // free lifetime in `Borrowed` in the first place.
+    fn borrow(&self) -> &AccessKey<'a> {
+        debug_assert_eq!(
+            (::std::mem::size_of::<OwnedKey>(), ::std::mem::align_of::<OwnedKey>()),
+            (::std::mem::size_of::<AccessKey<'a>>(), ::std::mem::align_of::<AccessKey<'a>>()),
+            "OwnedKey and AccessKey must stay layout-compatible for this cast to be sound"
+        );
+
+        unsafe { &*(self as *const OwnedKey as *const AccessKey<'a>) }
+    }
+}
+
+/// The error returned by `AssetCache::load` when either the `Format` or the
+/// `Asset` conversion step fails.
+pub enum LoadError<F, A>
+    where F: Format,
+          A: Asset
+{
+    /// The bytes could not be parsed into `Data` by the `Format`.
+    Format(F::Error),
+    /// The parsed `Data` could not be turned into the `Asset`.
+    Asset(A::Error),
+}
+
+impl<F, A> fmt::Display for LoadError<F, A>
+    where F: Format,
+          A: Asset
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Format(ref err) => write!(f, "failed to parse asset: {}", err),
+            LoadError::Asset(ref err) => write!(f, "failed to build asset: {}", err),
+        }
+    }
+}
+
+impl<F, A> fmt::Debug for LoadError<F, A>
+    where F: Format,
+          A: Asset
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Format(ref err) => f.debug_tuple("Format").field(err).finish(),
+            LoadError::Asset(ref err) => f.debug_tuple("Asset").field(err).finish(),
+        }
+    }
+}
+
+impl<F, A> Error for LoadError<F, A>
+    where F: Format,
+          A: Asset
+{
+}
+
+/// A type-erased cache that stores every loaded asset, of any `Asset` type,
+/// behind a single map keyed on `(TypeId, name)`. This is what lets a caller
+/// ask "is this asset loaded, of any type" in one place, instead of every
+/// `Asset::Context` keeping its own private `Cache<T>`.
+pub struct AssetCache {
+    entries: RwLock<FnvHashMap<OwnedKey, Box<dyn Any + Send + Sync>>>,
+    /// Reverse dependency edges: for each `AssetSpec` that some entry
+    /// declared as a dependency, the keys of the entries that depend on it.
+    /// Consulted by `invalidate` to find what needs to be dropped when a
+    /// dependency changes.
+    dependents: RwLock<FnvHashMap<AssetSpec, Vec<OwnedKey>>>,
+    /// The inverse of `dependents`: for each entry's key, the specs it
+    /// declared as dependencies when it was loaded. Consulted when an entry
+    /// is removed so its now-stale edges in `dependents` can be cleaned up
+    /// too, instead of lingering and pointing at a key that's gone.
+    dependencies_of: RwLock<FnvHashMap<OwnedKey, Vec<AssetSpec>>>,
+    /// The labeled sub-asset keys inserted for each primary entry's key, so
+    /// that removing the primary entry (e.g. via `invalidate`) cascades to
+    /// its sub-assets instead of leaving them behind as orphans.
+    labels_of: RwLock<FnvHashMap<OwnedKey, Vec<OwnedKey>>>,
+    /// Fallback values registered per asset type, handed back by
+    /// `load_with_policy` under `CacheMissPolicy::ReturnPlaceholder`.
+    placeholders: RwLock<FnvHashMap<TypeId, Box<dyn Any + Send + Sync>>>,
+}
+
+impl AssetCache {
+    /// Creates a new, empty `AssetCache`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Loads the asset named `name`, returning a cached `Handle` if one of
+    /// the same concrete type is already present. Otherwise, `bytes` is run
+    /// through `format.parse_with_deps` and `Asset::from_data`: the
+    /// resulting handle is inserted under `(TypeId::of::<A>(), name)`, any
+    /// labeled sub-assets are inserted under `(type, "{name}#{label}")`, and
+    /// any declared dependencies are recorded so that `invalidate` can later
+    /// drop this entry if one of them changes.
+    pub fn load<A, F>(
+        &self,
+        name: &str,
+        format: &F,
+        context: &A::Context,
+        bytes: Vec<u8>,
+    ) -> Result<Handle<A>, LoadError<F, A>>
+        where A: Asset + Send + Sync + 'static,
+              F: Format<Data = A::Data>,
+    {
+        let key = AccessKey {
+            type_id: TypeId::of::<A>(),
+            name,
+        };
+
+        if let Some(handle) = self.entries
+            .read()
+            .get(&key)
+            .and_then(|entry| entry.downcast_ref::<Handle<A>>())
+        {
+            return Ok(handle.clone());
+        }
+
+        let loaded = format.parse_with_deps(bytes).map_err(LoadError::Format)?;
+        let asset = A::from_data(loaded.value, context).map_err(LoadError::Asset)?;
+        let handle = Handle::new(asset);
+
+        let owned_key = OwnedKey {
+            type_id: TypeId::of::<A>(),
+            name: name.into(),
+        };
+
+        if !loaded.dependencies.is_empty() {
+            let mut dependents = self.dependents.write();
+            for dependency in &loaded.dependencies {
+                dependents.entry(dependency.clone()).or_insert_with(Vec::new).push(owned_key.clone());
+            }
+            self.dependencies_of.write().insert(owned_key.clone(), loaded.dependencies);
+        }
+
+        let mut label_keys = Vec::with_capacity(loaded.labeled.len());
+        let mut entries = self.entries.write();
+        for (label, sub_asset) in loaded.labeled {
+            let label_key = OwnedKey {
+                type_id: sub_asset.type_id(),
+                name: format!("{}#{}", name, label).into(),
+            };
+            entries.insert(label_key.clone(), sub_asset.into_boxed_any());
+            label_keys.push(label_key);
+        }
+        if !label_keys.is_empty() {
+            self.labels_of.write().insert(owned_key.clone(), label_keys);
+        }
+        entries.insert(owned_key, Box::new(handle.clone()));
+
+        Ok(handle)
+    }
+
+    /// Looks up a labeled sub-asset previously produced by a
+    /// `parse_with_deps` call, addressed as `"{name}#{label}"` (e.g.
+    /// `"level.ron#spawn_point"`).
+    ///
+    /// Labeled sub-assets are stored as a `Handle<A>`, the same as a
+    /// primary asset loaded through `load`, so this and `load` agree on how
+    /// an entry of type `A` is represented in `entries`.
+    pub fn get_labeled<A>(&self, name: &str, label: &str) -> Option<Handle<A>>
+        where A: Send + Sync + 'static
+    {
+        let full_name = format!("{}#{}", name, label);
+        let key = AccessKey {
+            type_id: TypeId::of::<A>(),
+            name: &full_name,
+        };
+
+        self.entries.read().get(&key).and_then(|entry| entry.downcast_ref::<Handle<A>>()).cloned()
+    }
+
+    /// Drops every cached entry that declared `spec` as a dependency, so a
+    /// future `load` call re-parses them against the new value. Labeled
+    /// sub-assets inserted alongside a dropped entry are dropped with it,
+    /// and its own dependency edges are cleaned out of `dependents` too, so
+    /// no stale keys are left behind on either side.
+    pub fn invalidate(&self, spec: &AssetSpec) {
+        // Bind the removed vec to a local and let the write guard drop here,
+        // before the loop calls into `remove_entry`: that function takes its
+        // own `self.dependents.write()` lock (to clean up stale edges for
+        // entries depending on more than one spec), and `RwLock` isn't
+        // reentrant, so holding this guard across the loop would deadlock
+        // the very first time `remove_entry` ran.
+        let dependents = self.dependents.write().remove(spec);
+
+        if let Some(dependents) = dependents {
+            for key in dependents {
+                self.remove_entry(key);
+            }
+        }
+    }
+
+    /// Removes `key` from `entries`, cascading to its labeled sub-assets
+    /// and cleaning up its dependency edges in `dependents`.
+    fn remove_entry(&self, key: OwnedKey) {
+        self.entries.write().remove(&key);
+
+        if let Some(label_keys) = self.labels_of.write().remove(&key) {
+            let mut entries = self.entries.write();
+            for label_key in label_keys {
+                entries.remove(&label_key);
+            }
+        }
+
+        if let Some(dependencies) = self.dependencies_of.write().remove(&key) {
+            let mut dependents = self.dependents.write();
+            for dependency in dependencies {
+                if let Some(keys) = dependents.get_mut(&dependency) {
+                    keys.retain(|k| *k != key);
+                }
+            }
+        }
+    }
+
+    /// Registers `placeholder` as the fallback value `load_with_policy`
+    /// hands back for `A` under `CacheMissPolicy::ReturnPlaceholder`, while
+    /// the real asset is loaded some other way.
+    pub fn register_placeholder<A>(&self, placeholder: A)
+        where A: Send + Sync + 'static
+    {
+        self.placeholders.write().insert(TypeId::of::<A>(), Box::new(Handle::new(placeholder)));
+    }
+
+    /// Like `load`, but consults `policy` before doing any blocking work,
+    /// so callers control what happens on a cache miss:
+    ///
+    /// * `BlockUntilLoaded` calls `bytes` and runs the same pipeline as
+    ///   `load` (the current, default behavior).
+    /// * `ErrorOnMiss` returns `PolicyError::NotCached` without calling
+    ///   `bytes` at all, for hot-path code that must never stall on I/O.
+    /// * `ReturnPlaceholder` hands back the fallback registered via
+    ///   `register_placeholder`, again without calling `bytes`.
+    ///
+    /// `bytes` is only invoked for `BlockUntilLoaded`, and only on a cache
+    /// miss.
+    pub fn load_with_policy<A, F>(
+        &self,
+        name: &str,
+        format: &F,
+        context: &A::Context,
+        bytes: impl FnOnce() -> Vec<u8>,
+        policy: CacheMissPolicy,
+    ) -> Result<Handle<A>, PolicyError<F, A>>
+        where A: Asset + Send + Sync + 'static,
+              F: Format<Data = A::Data>,
+    {
+        let key = AccessKey {
+            type_id: TypeId::of::<A>(),
+            name,
+        };
+
+        if let Some(handle) = self.entries
+            .read()
+            .get(&key)
+            .and_then(|entry| entry.downcast_ref::<Handle<A>>())
+        {
+            return Ok(handle.clone());
+        }
+
+        match policy {
+            CacheMissPolicy::BlockUntilLoaded => {
+                self.load(name, format, context, bytes()).map_err(PolicyError::Load)
+            }
+            CacheMissPolicy::ErrorOnMiss => Err(PolicyError::NotCached),
+            CacheMissPolicy::ReturnPlaceholder => {
+                self.placeholders
+                    .read()
+                    .get(&TypeId::of::<A>())
+                    .and_then(|entry| entry.downcast_ref::<Handle<A>>())
+                    .cloned()
+                    .ok_or(PolicyError::MissingPlaceholder)
+            }
+        }
+    }
+}
+
+impl Default for AssetCache {
+    fn default() -> Self {
+        AssetCache {
+            entries: Default::default(),
+            dependents: Default::default(),
+            dependencies_of: Default::default(),
+            labels_of: Default::default(),
+            placeholders: Default::default(),
+        }
+    }
+}
+
+/// Controls what happens when a requested `AssetSpec` isn't already in the
+/// cache.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheMissPolicy {
+    /// Run the `Format` parse synchronously and cache the result. This is
+    /// the default, and matches the behavior of `AssetCache::load`.
+    BlockUntilLoaded,
+    /// Return a dedicated "not cached" error without touching the
+    /// filesystem. Useful for hot-path frame code that must never stall on
+    /// I/O.
+    ErrorOnMiss,
+    /// Hand back a registered fallback asset of the same type while the
+    /// real load is deferred to happen some other way.
+    ReturnPlaceholder,
+}
+
+impl Default for CacheMissPolicy {
+    fn default() -> Self {
+        CacheMissPolicy::BlockUntilLoaded
+    }
+}
+
+/// The error returned by `AssetCache::load_with_policy`.
+pub enum PolicyError<F, A>
+    where F: Format,
+          A: Asset
+{
+    /// `CacheMissPolicy::ErrorOnMiss` was in effect and the asset wasn't
+    /// already cached.
+    NotCached,
+    /// `CacheMissPolicy::ReturnPlaceholder` was in effect but no
+    /// placeholder had been registered for this asset type.
+    MissingPlaceholder,
+    /// `CacheMissPolicy::BlockUntilLoaded` was in effect and the load
+    /// failed; see `LoadError`.
+    Load(LoadError<F, A>),
+}
+
+impl<F, A> fmt::Display for PolicyError<F, A>
+    where F: Format,
+          A: Asset
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PolicyError::NotCached => write!(f, "asset is not cached"),
+            PolicyError::MissingPlaceholder => write!(f, "no placeholder registered for this asset type"),
+            PolicyError::Load(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl<F, A> fmt::Debug for PolicyError<F, A>
+    where F: Format,
+          A: Asset
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PolicyError::NotCached => f.write_str("NotCached"),
+            PolicyError::MissingPlaceholder => f.write_str("MissingPlaceholder"),
+            PolicyError::Load(ref err) => f.debug_tuple("Load").field(err).finish(),
+        }
+    }
+}
+
+impl<F, A> Error for PolicyError<F, A>
+    where F: Format,
+          A: Asset
+{
+}
+
+/// A single backend that can resolve an asset's bytes by name and
+/// extension, yielding its own `StoreId` so that the resulting `AssetSpec`
+/// still uniquely identifies where the asset came from.
+pub trait Storage: Send + Sync {
+    /// Returns this storage's `StoreId`.
+    fn id(&self) -> StoreId;
+
+    /// Returns the bytes for `name.ext`, or `None` if this storage doesn't
+    /// have it.
+    fn load(&self, name: &str, ext: &str) -> Option<Vec<u8>>;
+}
+
+/// Resolves an asset from an ordered list of `Storage` backends, trying
+/// each in turn and returning the bytes together with the `StoreId` of
+/// whichever one satisfied the request.
+///
+/// This mirrors how a git object store is searched: loose objects first,
+/// then packs, then alternates. Push the storage that should win on a
+/// conflict first, e.g. a loose, on-disk directory ahead of a packed
+/// archive, so development-time overrides take priority over what shipped
+/// in the archive.
+#[derive(Default)]
+pub struct StoreChain {
+    storages: Vec<Box<dyn Storage>>,
+}
+
+impl StoreChain {
+    /// Creates an empty `StoreChain`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Appends `storage` to the end of the chain, i.e. it's tried last.
+    pub fn push<S: Storage + 'static>(&mut self, storage: S) -> &mut Self {
+        self.storages.push(Box::new(storage));
+        self
+    }
+
+    /// Tries each storage in order and returns the bytes and `StoreId` of
+    /// the first one that has `name.ext`, or `None` if none of them do.
+    pub fn load(&self, name: &str, ext: &str) -> Option<(Vec<u8>, StoreId)> {
+        for storage in &self.storages {
+            if let Some(bytes) = storage.load(name, ext) {
+                return Some((bytes, storage.id()));
+            }
+        }
+
+        None
+    }
+}
+
+fn read_u32(cursor: &mut &[u8]) -> io::Result<u32> {
+    if cursor.len() < 4 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "packed archive index truncated"));
+    }
+
+    let (bytes, rest) = cursor.split_at(4);
+    *cursor = rest;
+
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// A `Storage` backend that serves many assets out of one packed archive
+/// file, memory-mapped so a shipped game can bundle all of its assets
+/// without paying for an individual file open per asset; development
+/// builds can instead use a loose, on-disk directory and put it first in a
+/// `StoreChain`.
+///
+/// The archive is a flat index of `(key_len: u32, key: [u8], offset: u32,
+/// length: u32)` entries, prefixed by a `u32` entry count, followed by the
+/// concatenated asset bytes. `key` is `"name.ext"`.
+pub struct PackedStorage {
+    id: StoreId,
+    mmap: Mmap,
+    index: FnvHashMap<Box<str>, (usize, usize)>,
+}
+
+impl PackedStorage {
+    /// Memory-maps the packed archive at `path` and reads its index.
+    pub fn open<P: AsRef<Path>>(path: P, id: StoreId) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let mut index = FnvHashMap::default();
+        let mut cursor = &mmap[..];
+        let entry_count = read_u32(&mut cursor)?;
+
+        for _ in 0..entry_count {
+            let key_len = read_u32(&mut cursor)? as usize;
+            if key_len > cursor.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "packed archive index truncated"));
+            }
+            let key = ::std::str::from_utf8(&cursor[..key_len])
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+                .to_owned()
+                .into_boxed_str();
+            cursor = &cursor[key_len..];
+
+            let offset = read_u32(&mut cursor)? as usize;
+            let length = read_u32(&mut cursor)? as usize;
+            let in_bounds = offset.checked_add(length).map_or(false, |end| end <= mmap.len());
+            if !in_bounds {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "packed archive entry out of bounds"));
+            }
+
+            index.insert(key, (offset, length));
+        }
+
+        Ok(PackedStorage { id, mmap, index })
+    }
+}
+
+impl Storage for PackedStorage {
+    fn id(&self) -> StoreId {
+        self.id
+    }
+
+    fn load(&self, name: &str, ext: &str) -> Option<Vec<u8>> {
+        let key = format!("{}.{}", name, ext);
+        let &(offset, length) = self.index.get(key.as_str())?;
+        let end = offset.checked_add(length)?;
+
+        self.mmap.get(offset..end).map(|bytes| bytes.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct TestError;
+
+    impl fmt::Display for TestError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "test error")
+        }
+    }
+
+    impl Error for TestError {}
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Number(u32);
+
+    impl Asset for Number {
+        type Context = ();
+        type Data = u32;
+        type Error = TestError;
+
+        fn category() -> &'static str {
+            "number"
+        }
+
+        fn from_data(data: u32, _context: &()) -> Result<Self, TestError> {
+            Ok(Number(data))
+        }
+    }
+
+    struct NumberFormat;
+
+    impl Format for NumberFormat {
+        type Data = u32;
+        type Error = TestError;
+
+        fn extension() -> &'static str {
+            "num"
+        }
+
+        fn parse(&self, bytes: Vec<u8>) -> Result<u32, TestError> {
+            bytes.get(0).map(|&b| b as u32).ok_or(TestError)
+        }
+
+        fn parse_with_deps(&self, bytes: Vec<u8>) -> Result<LoadedAsset<u32>, TestError> {
+            let value = self.parse(bytes)?;
+
+            Ok(LoadedAsset::new(value).with_label("double", ErasedAsset::new(value * 2)))
+        }
+    }
+
+    #[test]
+    fn load_and_get_labeled_round_trip() {
+        let cache = AssetCache::new();
+
+        let handle = cache.load::<Number, _>("n", &NumberFormat, &(), vec![21]).unwrap();
+        assert_eq!(*handle, Number(21));
+
+        let labeled = cache.get_labeled::<u32>("n", "double").expect("labeled sub-asset should be found");
+        assert_eq!(*labeled, 42);
+
+        // A second load of the same name should hit the cache instead of
+        // re-parsing: different bytes, same (stale) cached value back.
+        let handle = cache.load::<Number, _>("n", &NumberFormat, &(), vec![99]).unwrap();
+        assert_eq!(*handle, Number(21));
+    }
+
+    struct DependentFormat {
+        dependency: AssetSpec,
+    }
+
+    impl Format for DependentFormat {
+        type Data = u32;
+        type Error = TestError;
+
+        fn extension() -> &'static str {
+            "dep"
+        }
+
+        fn parse(&self, bytes: Vec<u8>) -> Result<u32, TestError> {
+            bytes.get(0).map(|&b| b as u32).ok_or(TestError)
+        }
+
+        fn parse_with_deps(&self, bytes: Vec<u8>) -> Result<LoadedAsset<u32>, TestError> {
+            let value = self.parse(bytes)?;
+
+            Ok(LoadedAsset::new(value)
+                .with_label("half", ErasedAsset::new(value / 2))
+                .with_dependency(self.dependency.clone()))
+        }
+    }
+
+    #[test]
+    fn invalidate_cascades_to_dependents_and_labels() {
+        let cache = AssetCache::new();
+        let dependency = AssetSpec::new("base".to_string(), "num", StoreId::default());
+        let format = DependentFormat { dependency: dependency.clone() };
+
+        cache.load::<Number, _>("derived", &format, &(), vec![10]).unwrap();
+        assert!(cache.get_labeled::<u32>("derived", "half").is_some());
+
+        cache.invalidate(&dependency);
+
+        assert!(cache.get_labeled::<u32>("derived", "half").is_none(), "labeled sub-asset should be dropped along with its parent");
+
+        // Reloading re-runs the pipeline instead of hitting a stale entry.
+        let handle = cache.load::<Number, _>("derived", &format, &(), vec![20]).unwrap();
+        assert_eq!(*handle, Number(20));
+    }
+
+    #[cfg(feature = "hot-reload")]
+    struct SingleWatch {
+        spec: AssetSpec,
+        bytes: ::std::cell::RefCell<Vec<u8>>,
+        marker: ::std::cell::Cell<u64>,
+    }
+
+    #[cfg(feature = "hot-reload")]
+    impl Watch for SingleWatch {
+        fn watch(&self, spec: &AssetSpec) -> Option<(Vec<u8>, u64)> {
+            if *spec == self.spec {
+                Some((self.bytes.borrow().clone(), self.marker.get()))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[cfg(feature = "hot-reload")]
+    #[test]
+    fn hot_reload_skips_unchanged_and_rebuilds_changed() {
+        let cache: Cache<Number> = Cache::new();
+        let spec = AssetSpec::new("n".to_string(), "num", StoreId::default());
+
+        cache.insert_watched(spec.clone(), Number(1), 0);
+
+        let watch = SingleWatch {
+            spec: spec.clone(),
+            bytes: ::std::cell::RefCell::new(vec![1]),
+            marker: ::std::cell::Cell::new(0),
+        };
+
+        // Same marker as the baseline recorded by `insert_watched`: skipped.
+        cache.hot_reload(&NumberFormat, &(), &watch);
+        assert_eq!(cache.get(&spec), Some(Number(1)));
+
+        // Bytes and marker both change: rebuilt.
+        *watch.bytes.borrow_mut() = vec![2];
+        watch.marker.set(1);
+        cache.hot_reload(&NumberFormat, &(), &watch);
+        assert_eq!(cache.get(&spec), Some(Number(2)));
+    }
+
+    #[test]
+    fn shard_round_trip() {
+        let cache: Cache<u32> = Cache::new();
+
+        for i in 0..64u32 {
+            let spec = AssetSpec::new(format!("asset-{}", i), "test", StoreId::default());
+            cache.insert(spec, i);
+        }
+
+        for i in 0..64u32 {
+            let spec = AssetSpec::new(format!("asset-{}", i), "test", StoreId::default());
+            assert_eq!(cache.get(&spec), Some(i));
+        }
+
+        cache.clear_all();
+
+        let spec = AssetSpec::new("asset-0".to_string(), "test", StoreId::default());
+        assert_eq!(cache.get(&spec), None);
+    }
+
+    #[test]
+    fn packed_storage_open_and_load_round_trip() {
+        let key = b"foo.num";
+        let data = b"payload";
+
+        let mut archive = Vec::new();
+        archive.extend_from_slice(&1u32.to_le_bytes());
+        archive.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        archive.extend_from_slice(key);
+
+        // The data follows the index, so its offset is wherever the index
+        // ends once the offset/length fields we're about to write are
+        // accounted for.
+        let offset = archive.len() + 4 + 4;
+        archive.extend_from_slice(&(offset as u32).to_le_bytes());
+        archive.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        archive.extend_from_slice(data);
+
+        let path = ::std::env::temp_dir().join(format!("amethyst-assets-test-{}.pack", ::std::process::id()));
+        ::std::fs::write(&path, &archive).unwrap();
+
+        let storage = PackedStorage::open(&path, StoreId::default()).unwrap();
+        assert_eq!(storage.load("foo", "num"), Some(data.to_vec()));
+        assert_eq!(storage.load("missing", "num"), None);
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cache_miss_policy_controls_miss_behavior() {
+        let cache = AssetCache::new();
+
+        let err = cache
+            .load_with_policy::<Number, NumberFormat>(
+                "missing",
+                &NumberFormat,
+                &(),
+                || panic!("ErrorOnMiss must not read bytes"),
+                CacheMissPolicy::ErrorOnMiss,
+            )
+            .unwrap_err();
+        assert!(matches!(err, PolicyError::NotCached));
+
+        let err = cache
+            .load_with_policy::<Number, NumberFormat>(
+                "missing",
+                &NumberFormat,
+                &(),
+                || panic!("ReturnPlaceholder must not read bytes"),
+                CacheMissPolicy::ReturnPlaceholder,
+            )
+            .unwrap_err();
+        assert!(matches!(err, PolicyError::MissingPlaceholder));
+
+        cache.register_placeholder(Number(0));
+
+        let placeholder = cache
+            .load_with_policy::<Number, NumberFormat>(
+                "missing",
+                &NumberFormat,
+                &(),
+                || panic!("ReturnPlaceholder must not read bytes"),
+                CacheMissPolicy::ReturnPlaceholder,
+            )
+            .unwrap();
+        assert_eq!(*placeholder, Number(0));
+
+        let handle = cache
+            .load_with_policy::<Number, NumberFormat>(
+                "n",
+                &NumberFormat,
+                &(),
+                || vec![7],
+                CacheMissPolicy::BlockUntilLoaded,
+            )
+            .unwrap();
+        assert_eq!(*handle, Number(7));
+    }
 }